@@ -0,0 +1,412 @@
+//! Pull-based (Volcano) execution engine for `RelExpr`.
+//!
+//! Each plan node compiles into an [`Operator`] that yields one [`Row`] at a
+//! time from its `next()`. Running a decorrelated plan this way lets us check
+//! that the rewrites in the optimizer actually preserve results end-to-end.
+
+use std::collections::HashMap;
+
+use crate::{AggFunc, Expr, ExprId, JoinKind, RelExpr, RelId, State};
+
+/// A tuple: column id -> value. The column ids are the same ones that appear
+/// in `Scan` and flow through `att()`.
+pub type Row = HashMap<usize, i64>;
+
+/// In-memory set of named tables.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    tables: HashMap<String, Vec<Row>>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Catalog {
+            tables: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, rows: Vec<Row>) {
+        self.tables.insert(name.into(), rows);
+    }
+
+    fn rows(&self, name: &str) -> Vec<Row> {
+        self.tables.get(name).cloned().unwrap_or_default()
+    }
+}
+
+/// A node in the running pipeline. `next()` returns `None` once exhausted.
+pub trait Operator {
+    fn next(&mut self) -> Option<Row>;
+}
+
+/// Evaluate a scalar expression against a row, with `outer` supplying any
+/// correlated columns bound by an enclosing `FlatMap`.
+fn eval(state: &State, catalog: &Catalog, expr: ExprId, row: &Row, outer: &Row) -> i64 {
+    match state.expr(expr) {
+        Expr::ColRef { id } => *row
+            .get(&id)
+            .or_else(|| outer.get(&id))
+            .unwrap_or_else(|| panic!("unbound column @{}", id)),
+        Expr::Int { val } => val,
+        Expr::Eq { left, right } => {
+            let l = eval(state, catalog, left, row, outer);
+            let r = eval(state, catalog, right, row, outer);
+            (l == r) as i64
+        }
+        Expr::Plus { left, right } => {
+            eval(state, catalog, left, row, outer) + eval(state, catalog, right, row, outer)
+        }
+        Expr::Subquery { expr } => {
+            // The subquery sees the current row's columns as correlations.
+            let mut env = outer.clone();
+            env.extend(row.clone());
+            let rows = execute(expr, state, catalog, env);
+            assert!(
+                rows.len() == 1,
+                "scalar subquery produced {} rows",
+                rows.len()
+            );
+            let row = &rows[0];
+            assert!(
+                row.len() == 1,
+                "scalar subquery produced {} columns",
+                row.len()
+            );
+            *row.values().next().unwrap()
+        }
+    }
+}
+
+/// Compile and drain a plan into its full result set.
+pub fn execute(rel: RelId, state: &State, catalog: &Catalog, outer: Row) -> Vec<Row> {
+    let mut op = compile(rel, state, catalog, outer);
+    let mut rows = Vec::new();
+    while let Some(row) = op.next() {
+        rows.push(row);
+    }
+    rows
+}
+
+/// Compile a plan node into an operator tree. `outer` carries correlated
+/// bindings down from an enclosing `FlatMap`.
+pub fn compile<'a>(
+    rel: RelId,
+    state: &'a State,
+    catalog: &'a Catalog,
+    outer: Row,
+) -> Box<dyn Operator + 'a> {
+    match state.rel(rel) {
+        RelExpr::Scan {
+            table_name,
+            column_names,
+        } => {
+            // Project each stored tuple down to the columns this `Scan`
+            // actually declares, so downstream operators never see ids the
+            // plan didn't ask for.
+            let cols: std::collections::HashSet<usize> = column_names.into_iter().collect();
+            let rows: Vec<Row> = catalog
+                .rows(&table_name)
+                .into_iter()
+                .map(|mut row| {
+                    row.retain(|id, _| cols.contains(id));
+                    row
+                })
+                .collect();
+            Box::new(Scan {
+                rows: rows.into_iter(),
+            })
+        }
+        RelExpr::Select { src, predicates } => Box::new(Select {
+            input: compile(src, state, catalog, outer.clone()),
+            predicates,
+            state,
+            catalog,
+            outer,
+        }),
+        RelExpr::Project { src, cols } => Box::new(Project {
+            input: compile(src, state, catalog, outer),
+            cols,
+        }),
+        RelExpr::Map { input, exprs } => Box::new(Map {
+            input: compile(input, state, catalog, outer.clone()),
+            exprs,
+            state,
+            catalog,
+            outer,
+        }),
+        RelExpr::Join {
+            kind,
+            left,
+            right,
+            predicates,
+        } => {
+            // Buffer the right side, then iterate the left (nested loop).
+            let buffer = execute(right, state, catalog, outer.clone());
+            Box::new(Join {
+                kind,
+                left: compile(left, state, catalog, outer.clone()),
+                buffer,
+                cursor: 0,
+                current: None,
+                matched: false,
+                predicates,
+                state,
+                catalog,
+                outer,
+            })
+        }
+        RelExpr::FlatMap { input, func } => Box::new(FlatMap {
+            input: compile(input, state, catalog, outer.clone()),
+            func,
+            pending: Vec::new().into_iter(),
+            state,
+            catalog,
+            outer,
+        }),
+        RelExpr::Aggregate {
+            input,
+            group_by,
+            aggs,
+        } => {
+            // Aggregation is blocking, so materialize the input and fold groups
+            // eagerly, then stream the result like a table.
+            let rows = execute(input, state, catalog, outer.clone());
+            let out = aggregate(rows, &group_by, &aggs, state, catalog, &outer);
+            Box::new(Scan {
+                rows: out.into_iter(),
+            })
+        }
+    }
+}
+
+/// Collect the column ids an expression reads. A subquery's own free columns
+/// are its correlations, so they count too.
+fn expr_cols(expr: ExprId, state: &State) -> std::collections::HashSet<usize> {
+    let mut cols = std::collections::HashSet::new();
+    let mut stack = vec![expr];
+    while let Some(e) = stack.pop() {
+        match state.expr(e) {
+            Expr::ColRef { id } => {
+                cols.insert(id);
+            }
+            Expr::Int { .. } => {}
+            Expr::Eq { left, right } | Expr::Plus { left, right } => {
+                stack.push(left);
+                stack.push(right);
+            }
+            Expr::Subquery { expr } => {
+                cols.extend(expr.free(state));
+            }
+        }
+    }
+    cols
+}
+
+fn aggregate(
+    rows: Vec<Row>,
+    group_by: &std::collections::HashSet<usize>,
+    aggs: &[(usize, AggFunc, ExprId)],
+    state: &State,
+    catalog: &Catalog,
+    outer: &Row,
+) -> Vec<Row> {
+    let mut keys: Vec<usize> = group_by.iter().copied().collect();
+    keys.sort_unstable();
+
+    let mut groups: HashMap<Vec<i64>, Vec<Row>> = HashMap::new();
+    for row in rows {
+        let key: Vec<i64> = keys.iter().map(|c| row[c]).collect();
+        groups.entry(key).or_default().push(row);
+    }
+
+    // A scalar aggregate over an empty input still yields one (empty-key) group.
+    if groups.is_empty() && group_by.is_empty() {
+        groups.insert(Vec::new(), Vec::new());
+    }
+
+    let mut out = Vec::new();
+    for (key, grp) in groups {
+        let mut row = Row::new();
+        for (col, val) in keys.iter().zip(&key) {
+            row.insert(*col, *val);
+        }
+        for (id, func, expr) in aggs {
+            // NULL-aware: only rows where the aggregated expression is defined
+            // (all of its columns are present) contribute. After a left-outer
+            // join the null-extended rows lack the inner columns, so they drop
+            // out here and an empty group folds to the identity — which is what
+            // keeps `COUNT` of an unmatched outer tuple at 0 rather than 1.
+            let cols = expr_cols(*expr, state);
+            let contributing = || {
+                grp.iter()
+                    .filter(|r| cols.iter().all(|c| r.contains_key(c)))
+            };
+            let vals = || contributing().map(|r| eval(state, catalog, *expr, r, outer));
+            let value = match func {
+                AggFunc::Count => contributing().count() as i64,
+                AggFunc::Sum => vals().sum(),
+                AggFunc::Min => vals().min().unwrap_or(0),
+                AggFunc::Max => vals().max().unwrap_or(0),
+            };
+            row.insert(*id, value);
+        }
+        out.push(row);
+    }
+    out
+}
+
+struct Scan {
+    rows: std::vec::IntoIter<Row>,
+}
+
+impl Operator for Scan {
+    fn next(&mut self) -> Option<Row> {
+        self.rows.next()
+    }
+}
+
+struct Select<'a> {
+    input: Box<dyn Operator + 'a>,
+    predicates: Vec<ExprId>,
+    state: &'a State,
+    catalog: &'a Catalog,
+    outer: Row,
+}
+
+impl Operator for Select<'_> {
+    fn next(&mut self) -> Option<Row> {
+        while let Some(row) = self.input.next() {
+            if self
+                .predicates
+                .iter()
+                .all(|p| eval(self.state, self.catalog, *p, &row, &self.outer) != 0)
+            {
+                return Some(row);
+            }
+        }
+        None
+    }
+}
+
+struct Project<'a> {
+    input: Box<dyn Operator + 'a>,
+    cols: std::collections::HashSet<usize>,
+}
+
+impl Operator for Project<'_> {
+    fn next(&mut self) -> Option<Row> {
+        let mut row = self.input.next()?;
+        row.retain(|k, _| self.cols.contains(k));
+        Some(row)
+    }
+}
+
+struct Map<'a> {
+    input: Box<dyn Operator + 'a>,
+    exprs: Vec<(usize, ExprId)>,
+    state: &'a State,
+    catalog: &'a Catalog,
+    outer: Row,
+}
+
+impl Operator for Map<'_> {
+    fn next(&mut self) -> Option<Row> {
+        let mut row = self.input.next()?;
+        for (id, expr) in &self.exprs {
+            let val = eval(self.state, self.catalog, *expr, &row, &self.outer);
+            row.insert(*id, val);
+        }
+        Some(row)
+    }
+}
+
+struct Join<'a> {
+    kind: JoinKind,
+    left: Box<dyn Operator + 'a>,
+    buffer: Vec<Row>,
+    cursor: usize,
+    current: Option<Row>,
+    matched: bool,
+    predicates: Vec<ExprId>,
+    state: &'a State,
+    catalog: &'a Catalog,
+    outer: Row,
+}
+
+impl Operator for Join<'_> {
+    fn next(&mut self) -> Option<Row> {
+        loop {
+            if self.current.is_none() {
+                self.current = Some(self.left.next()?);
+                self.cursor = 0;
+                self.matched = false;
+            }
+            let left = self.current.as_ref().unwrap();
+
+            while self.cursor < self.buffer.len() {
+                let right = &self.buffer[self.cursor];
+                self.cursor += 1;
+
+                let mut row = left.clone();
+                row.extend(right.clone());
+
+                if self
+                    .predicates
+                    .iter()
+                    .all(|p| eval(self.state, self.catalog, *p, &row, &self.outer) != 0)
+                {
+                    self.matched = true;
+                    return Some(row);
+                }
+            }
+
+            // A left-outer join emits the left tuple on its own when nothing on
+            // the right matched, leaving the right columns absent (NULL).
+            let emit_unmatched = matches!(self.kind, JoinKind::LeftOuter) && !self.matched;
+            let left = self.current.take().unwrap();
+            if emit_unmatched {
+                return Some(left);
+            }
+        }
+    }
+}
+
+struct FlatMap<'a> {
+    input: Box<dyn Operator + 'a>,
+    func: RelId,
+    pending: std::vec::IntoIter<Row>,
+    state: &'a State,
+    catalog: &'a Catalog,
+    outer: Row,
+}
+
+impl Operator for FlatMap<'_> {
+    fn next(&mut self) -> Option<Row> {
+        loop {
+            if let Some(row) = self.pending.next() {
+                return Some(row);
+            }
+
+            // Advance to the next outer row and run the correlated body.
+            let left = self.input.next()?;
+
+            // Bind the body's free columns out of the outer row.
+            let mut inner_outer = self.outer.clone();
+            for col in self.func.free(self.state) {
+                if let Some(val) = left.get(&col) {
+                    inner_outer.insert(col, *val);
+                }
+            }
+
+            let inner = execute(self.func, self.state, self.catalog, inner_outer);
+            let joined: Vec<Row> = inner
+                .into_iter()
+                .map(|mut r| {
+                    r.extend(left.clone());
+                    r
+                })
+                .collect();
+            self.pending = joined.into_iter();
+        }
+    }
+}
@@ -1,15 +1,63 @@
-use std::{backtrace, cell::RefCell, collections::HashSet, rc::Rc};
+mod exec;
+mod parse;
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum Rule {
     Hoist,
     Decorrelate,
+    ConstantFold,
+    PruneColumns,
+    PushPredicates,
+    PushProjectIntoMap,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AggFunc {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum JoinKind {
+    /// Only matching pairs survive.
+    Inner,
+    /// Every left tuple survives; a left tuple with no matching right tuple is
+    /// emitted once, with the right columns left absent (NULL).
+    LeftOuter,
+}
+
+/// Lightweight handle into `State`'s expression arena.
+///
+/// Ids are `Copy`, are never reused, and a node's children always have
+/// smaller ids than the node itself (arenas are append-only and nodes are
+/// interned bottom-up), so the `att`/`free` memo tables can be keyed by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ExprId(usize);
+
+/// Lightweight handle into `State`'s relational arena. See [`ExprId`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct RelId(usize);
+
 #[derive(Debug, Clone)]
 struct State {
     next_id: Rc<RefCell<usize>>,
     enabled_rules: Rc<RefCell<HashSet<Rule>>>,
+    // Append-only arenas: once a node is interned it is never mutated, so its
+    // id is a stable key for the caches below.
+    exprs: Rc<RefCell<Vec<Expr>>>,
+    rels: Rc<RefCell<Vec<RelExpr>>>,
+    // Lazily populated, keyed by id. Because nodes are immutable an entry is
+    // valid for the life of the `State`; we only ever insert, never clear.
+    att_cache: Rc<RefCell<HashMap<RelId, HashSet<usize>>>>,
+    free_cache: Rc<RefCell<HashMap<RelId, HashSet<usize>>>>,
 }
 
 impl State {
@@ -17,6 +65,10 @@ impl State {
         State {
             next_id: Rc::new(RefCell::new(0)),
             enabled_rules: Rc::new(RefCell::new(HashSet::new())),
+            exprs: Rc::new(RefCell::new(Vec::new())),
+            rels: Rc::new(RefCell::new(Vec::new())),
+            att_cache: Rc::new(RefCell::new(HashMap::new())),
+            free_cache: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 
@@ -34,71 +86,144 @@ impl State {
     fn enabled(&self, rule: Rule) -> bool {
         self.enabled_rules.borrow().contains(&rule)
     }
+
+    // --- arena ---
+
+    fn alloc_expr(&self, expr: Expr) -> ExprId {
+        let mut exprs = self.exprs.borrow_mut();
+        let id = ExprId(exprs.len());
+        exprs.push(expr);
+        id
+    }
+
+    fn alloc_rel(&self, rel: RelExpr) -> RelId {
+        let mut rels = self.rels.borrow_mut();
+        let id = RelId(rels.len());
+        rels.push(rel);
+        id
+    }
+
+    /// Clone a node out of the arena. Cheap: children are ids, not subtrees.
+    fn expr(&self, id: ExprId) -> Expr {
+        self.exprs.borrow()[id.0].clone()
+    }
+
+    fn rel(&self, id: RelId) -> RelExpr {
+        self.rels.borrow()[id.0].clone()
+    }
+
+    // --- scalar builder helpers ---
+
+    fn col_ref(&self, id: usize) -> ExprId {
+        self.alloc_expr(Expr::ColRef { id })
+    }
+
+    fn int(&self, val: i64) -> ExprId {
+        self.alloc_expr(Expr::Int { val })
+    }
+
+    fn subquery(&self, expr: RelId) -> ExprId {
+        self.alloc_expr(Expr::Subquery { expr })
+    }
+
+    fn scan(&self, table_name: String, column_names: Vec<usize>) -> RelId {
+        self.alloc_rel(RelExpr::Scan {
+            table_name,
+            column_names,
+        })
+    }
 }
 
+/// Scalar-expression functor: the recursion is pulled out into the generic
+/// slot `R`, so an `ExprF<ExprId>` is one arena node and `ExprF<A>` is the
+/// shape used while folding. The concrete [`Expr`] is the fixpoint `R = ExprId`.
 #[derive(Debug, Clone)]
-enum Expr {
+enum ExprF<R> {
     ColRef { id: usize },
     Int { val: i64 },
-    Eq { left: Box<Expr>, right: Box<Expr> },
-    Plus { left: Box<Expr>, right: Box<Expr> },
-    Subquery { expr: Box<RelExpr> },
+    Eq { left: R, right: R },
+    Plus { left: R, right: R },
+    Subquery { expr: RelId },
 }
 
-impl Expr {
-    fn col_ref(id: usize) -> Self {
-        Expr::ColRef { id }
+type Expr = ExprF<ExprId>;
+
+impl<R> ExprF<R> {
+    /// Rebuild the node with `f` applied to each scalar child, preserving the
+    /// variant, field order, and the `RelId` payload in `Subquery`. Completes
+    /// the scalar functor alongside [`RelExprF::map_children`]; scalar folds
+    /// currently recurse directly, so nothing calls it yet.
+    #[allow(dead_code)]
+    fn map_children<S>(self, mut f: impl FnMut(R) -> S) -> ExprF<S> {
+        match self {
+            ExprF::ColRef { id } => ExprF::ColRef { id },
+            ExprF::Int { val } => ExprF::Int { val },
+            ExprF::Eq { left, right } => ExprF::Eq {
+                left: f(left),
+                right: f(right),
+            },
+            ExprF::Plus { left, right } => ExprF::Plus {
+                left: f(left),
+                right: f(right),
+            },
+            ExprF::Subquery { expr } => ExprF::Subquery { expr },
+        }
     }
+}
 
-    fn int(val: i64) -> Self {
-        Expr::Int { val }
+impl ExprId {
+    fn eq(self, state: &State, other: ExprId) -> ExprId {
+        state.alloc_expr(Expr::Eq {
+            left: self,
+            right: other,
+        })
     }
 
-    fn eq(self, other: Self) -> Self {
-        Expr::Eq {
-            left: Box::new(self),
-            right: Box::new(other),
-        }
+    fn plus(self, state: &State, other: ExprId) -> ExprId {
+        state.alloc_expr(Expr::Plus {
+            left: self,
+            right: other,
+        })
     }
 
-    fn free(&self) -> HashSet<usize> {
-        match self {
+    fn free(self, state: &State) -> HashSet<usize> {
+        match state.expr(self) {
             Expr::ColRef { id } => {
                 let mut set = HashSet::new();
-                set.insert(*id);
+                set.insert(id);
                 set
             }
             Expr::Int { .. } => HashSet::new(),
             Expr::Eq { left, right } => {
-                let mut set = left.free();
-                set.extend(right.free());
+                let mut set = left.free(state);
+                set.extend(right.free(state));
                 set
             }
             Expr::Plus { left, right } => {
-                let mut set = left.free();
-                set.extend(right.free());
+                let mut set = left.free(state);
+                set.extend(right.free(state));
                 set
             }
-            Expr::Subquery { expr } => expr.free(),
+            Expr::Subquery { expr } => expr.free(state),
         }
     }
 
-    fn bound_by(&self, rel: &RelExpr) -> bool {
-        self.free().is_subset(&rel.att())
+    fn bound_by(self, state: &State, rel: RelId) -> bool {
+        self.free(state).is_subset(&rel.att(state))
     }
 
-    fn has_subquery(&self) -> bool {
-        match self {
+    fn has_subquery(self, state: &State) -> bool {
+        match state.expr(self) {
             Expr::ColRef { .. } => false,
             Expr::Int { .. } => false,
-            Expr::Eq { left, right } => left.has_subquery() || right.has_subquery(),
-            Expr::Plus { left, right } => left.has_subquery() || right.has_subquery(),
+            Expr::Eq { left, right } => left.has_subquery(state) || right.has_subquery(state),
+            Expr::Plus { left, right } => left.has_subquery(state) || right.has_subquery(state),
             Expr::Subquery { .. } => true,
         }
     }
 
-    fn print(&self, indent: usize, out: &mut String) {
-        match self {
+    fn print(self, state: &State, indent: usize, out: &mut String) {
+        match state.expr(self) {
             Expr::ColRef { id } => {
                 out.push_str(&format!("@{}", id));
             }
@@ -106,306 +231,390 @@ impl Expr {
                 out.push_str(&format!("{}", val));
             }
             Expr::Eq { left, right } => {
-                left.print(indent, out);
+                left.print(state, indent, out);
                 out.push('=');
-                right.print(indent, out);
+                right.print(state, indent, out);
             }
             Expr::Plus { left, right } => {
-                left.print(indent, out);
+                left.print(state, indent, out);
                 out.push('+');
-                right.print(indent, out);
+                right.print(state, indent, out);
             }
             Expr::Subquery { expr } => {
                 out.push_str("λ.(\n");
-                expr.print(indent + 6, out);
+                expr.print(state, indent + 6, out);
                 out.push_str(&format!("{})", " ".repeat(indent + 4)));
             }
         }
     }
-
-    fn plus(self, other: Self) -> Self {
-        Expr::Plus {
-            left: Box::new(self),
-            right: Box::new(other),
-        }
-    }
 }
 
+/// Relational functor: relational children live in the generic slot `R`, while
+/// scalar payloads stay `ExprId`. The concrete [`RelExpr`] is the fixpoint
+/// `R = RelId`. Mirrors the "recursion out of the enum" shape in dhall_core.
 #[derive(Debug, Clone)]
-enum RelExpr {
+enum RelExprF<R> {
     Scan {
         table_name: String,
         column_names: Vec<usize>,
     },
     Select {
-        src: Box<RelExpr>,
-        predicates: Vec<Expr>,
+        src: R,
+        predicates: Vec<ExprId>,
     },
     Join {
-        left: Box<RelExpr>,
-        right: Box<RelExpr>,
-        predicates: Vec<Expr>,
+        kind: JoinKind,
+        left: R,
+        right: R,
+        predicates: Vec<ExprId>,
     },
     Project {
-        src: Box<RelExpr>,
+        src: R,
         cols: HashSet<usize>,
     },
     Map {
-        input: Box<RelExpr>,
-        exprs: Vec<(usize, Expr)>,
+        input: R,
+        exprs: Vec<(usize, ExprId)>,
     },
     FlatMap {
-        input: Box<RelExpr>,
-        func: Box<RelExpr>,
+        input: R,
+        func: R,
+    },
+    Aggregate {
+        input: R,
+        group_by: HashSet<usize>,
+        aggs: Vec<(usize, AggFunc, ExprId)>,
     },
 }
 
-impl RelExpr {
-    fn scan(table_name: String, column_names: Vec<usize>) -> Self {
-        RelExpr::Scan {
-            table_name,
-            column_names,
+type RelExpr = RelExprF<RelId>;
+
+impl<R> RelExprF<R> {
+    /// Rebuild the node with `f` applied to each relational child, preserving
+    /// the variant, field order, and the scalar (`ExprId`) payloads.
+    fn map_children<S>(self, mut f: impl FnMut(R) -> S) -> RelExprF<S> {
+        match self {
+            RelExprF::Scan {
+                table_name,
+                column_names,
+            } => RelExprF::Scan {
+                table_name,
+                column_names,
+            },
+            RelExprF::Select { src, predicates } => RelExprF::Select {
+                src: f(src),
+                predicates,
+            },
+            RelExprF::Join {
+                kind,
+                left,
+                right,
+                predicates,
+            } => RelExprF::Join {
+                kind,
+                left: f(left),
+                right: f(right),
+                predicates,
+            },
+            RelExprF::Project { src, cols } => RelExprF::Project { src: f(src), cols },
+            RelExprF::Map { input, exprs } => RelExprF::Map {
+                input: f(input),
+                exprs,
+            },
+            RelExprF::FlatMap { input, func } => RelExprF::FlatMap {
+                input: f(input),
+                func: f(func),
+            },
+            RelExprF::Aggregate {
+                input,
+                group_by,
+                aggs,
+            } => RelExprF::Aggregate {
+                input: f(input),
+                group_by,
+                aggs,
+            },
         }
     }
+}
 
-    fn select(self, mut predicates: Vec<Expr>) -> Self {
+impl RelId {
+    fn select(self, state: &State, mut predicates: Vec<ExprId>) -> RelId {
         if let RelExpr::Select {
             src,
             predicates: mut preds,
-        } = self
+        } = state.rel(self)
         {
             preds.append(&mut predicates);
-            return src.select(preds);
+            return src.select(state, preds);
         }
 
-        RelExpr::Select {
-            src: Box::new(self),
+        state.alloc_rel(RelExpr::Select {
+            src: self,
             predicates,
-        }
+        })
     }
 
-    fn join(self, other: Self, mut predicates: Vec<Expr>) -> Self {
-        for i in 0..predicates.len() {
-            if predicates[i].bound_by(&self) {
-                // We can push this predicate down.
-                let predicate = predicates.swap_remove(i);
-                return self.select(vec![predicate]).join(other, predicates);
-            }
-
-            if predicates[i].bound_by(&other) {
-                // We can push this predicate down.
-                let predicate = predicates.swap_remove(i);
-                return self.join(other.select(vec![predicate]), predicates);
-            }
-        }
+    /// Inner join. Predicate pushdown lives in the [`push_predicates`] rewrite
+    /// rule rather than here, so the builder just interns the node.
+    fn join(self, state: &State, other: RelId, predicates: Vec<ExprId>) -> RelId {
+        state.alloc_rel(RelExpr::Join {
+            kind: JoinKind::Inner,
+            left: self,
+            right: other,
+            predicates,
+        })
+    }
 
-        RelExpr::Join {
-            left: Box::new(self),
-            right: Box::new(other),
+    /// Left-outer join. Unlike an inner join, its predicates are never pushed
+    /// down ([`push_predicates`] only matches inner joins): a predicate on the
+    /// right side would suppress the null-extended rows that make the join
+    /// "outer", so the condition has to stay on the join itself.
+    fn join_outer(self, state: &State, other: RelId, predicates: Vec<ExprId>) -> RelId {
+        state.alloc_rel(RelExpr::Join {
+            kind: JoinKind::LeftOuter,
+            left: self,
+            right: other,
             predicates,
-        }
+        })
     }
 
-    fn has_subquery(&self) -> bool {
-        match self {
+    /// Whether any scalar anywhere in this subtree still carries a subquery.
+    /// The hoist rule checks individual expressions as it walks, so this whole
+    /// tree variant is not wired into the driver yet.
+    #[allow(dead_code)]
+    fn has_subquery(self, state: &State) -> bool {
+        match state.rel(self) {
             RelExpr::Scan { .. } => false,
-            RelExpr::Select { src, .. } => src.has_subquery(),
-            RelExpr::Join { left, right, .. } => left.has_subquery() || right.has_subquery(),
+            RelExpr::Select { src, .. } => src.has_subquery(state),
+            RelExpr::Join { left, right, .. } => {
+                left.has_subquery(state) || right.has_subquery(state)
+            }
             RelExpr::Map { input, exprs } => {
-                if input.has_subquery() {
+                if input.has_subquery(state) {
                     return true;
                 }
 
                 for (_, expr) in exprs {
-                    if expr.has_subquery() {
+                    if expr.has_subquery(state) {
                         return true;
                     }
                 }
 
                 false
             }
-            RelExpr::Project { src, .. } => src.has_subquery(),
+            RelExpr::Project { src, .. } => src.has_subquery(state),
             // TODO: wrong
-            RelExpr::FlatMap { input, func } => input.has_subquery() || func.has_subquery(),
+            RelExpr::FlatMap { input, func } => {
+                input.has_subquery(state) || func.has_subquery(state)
+            }
+            RelExpr::Aggregate { input, aggs, .. } => {
+                input.has_subquery(state) || aggs.iter().any(|(_, _, e)| e.has_subquery(state))
+            }
         }
     }
 
-    fn hoist(self, state: &State, id: usize, expr: Expr) -> Self {
-        match expr {
+    fn hoist(self, state: &State, id: usize, expr: ExprId) -> RelId {
+        match state.expr(expr) {
             Expr::Subquery { expr } => {
-                let att = expr.att();
+                let att = expr.att(state);
                 assert!(att.len() == 1);
-                let input_col_id = att.iter().next().unwrap();
-                let rhs = expr.map(state, vec![(id, Expr::ColRef { id: *input_col_id })]);
+                let input_col_id = *att.iter().next().unwrap();
+                let col = state.col_ref(input_col_id);
+                let rhs = expr.map(state, vec![(id, col)]);
                 self.flatmap(state, rhs)
             }
             Expr::Plus { left, right } => {
                 // Hoist the left, hoist the right, then perform the plus.
                 let lhs_id = state.next();
                 let rhs_id = state.next();
-                let att = self.att();
-                self.hoist(state, lhs_id, *left)
-                    .hoist(state, rhs_id, *right)
-                    .map(
-                        state,
-                        [(
-                            id,
-                            Expr::Plus {
-                                left: Box::new(Expr::ColRef { id: lhs_id }),
-                                right: Box::new(Expr::ColRef { id: rhs_id }),
-                            },
-                        )],
-                    )
-                    .project(state, att.into_iter().chain([id].into_iter()).collect())
+                let att = self.att(state);
+                let lhs = state.col_ref(lhs_id);
+                let rhs = state.col_ref(rhs_id);
+                let plus = lhs.plus(state, rhs);
+                self.hoist(state, lhs_id, left)
+                    .hoist(state, rhs_id, right)
+                    .map(state, [(id, plus)])
+                    .project(state, att.into_iter().chain([id]).collect())
+            }
+            Expr::Eq { left, right } => {
+                // Same shape as `Plus`: a subquery can sit on either side of the
+                // equality, so hoist both and rebuild the comparison on top.
+                let lhs_id = state.next();
+                let rhs_id = state.next();
+                let att = self.att(state);
+                let lhs = state.col_ref(lhs_id);
+                let rhs = state.col_ref(rhs_id);
+                let eq = lhs.eq(state, rhs);
+                self.hoist(state, lhs_id, left)
+                    .hoist(state, rhs_id, right)
+                    .map(state, [(id, eq)])
+                    .project(state, att.into_iter().chain([id]).collect())
             }
             Expr::Int { .. } | Expr::ColRef { .. } => self.map(state, vec![(id, expr)]),
-            x => unimplemented!("{:?}", x),
         }
     }
 
-    fn map(self, state: &State, exprs: impl IntoIterator<Item = (usize, Expr)>) -> Self {
-        let mut exprs: Vec<_> = exprs.into_iter().collect();
+    fn map(self, state: &State, exprs: impl IntoIterator<Item = (usize, ExprId)>) -> RelId {
+        let exprs: Vec<_> = exprs.into_iter().collect();
 
         if exprs.is_empty() {
             return self;
         }
 
-        // if let RelExpr::Map {
-        //     input,
-        //     exprs: mut existing,
-        // } = self
-        // {
-        //     existing.append(&mut exprs);
-        //     return RelExpr::Map {
-        //         input,
-        //         exprs: existing,
-        //     };
-        // }
-
-        if state.enabled(Rule::Hoist) {
-            for i in 0..exprs.len() {
-                // Only hoist expressions with subqueries.
-                if exprs[i].1.has_subquery() {
-                    let (id, expr) = exprs.swap_remove(i);
-                    return self.map(state, exprs).hoist(state, id, expr);
-                }
-            }
-        }
+        state.alloc_rel(RelExpr::Map { input: self, exprs })
+    }
 
-        RelExpr::Map {
-            input: Box::new(self),
-            exprs,
-        }
+    /// Dependent join. Decorrelation lives in the [`decorrelate`] rewrite rule
+    /// rather than here, so the builder just interns the node.
+    fn flatmap(self, state: &State, func: RelId) -> RelId {
+        state.alloc_rel(RelExpr::FlatMap { input: self, func })
     }
 
-    fn flatmap(self, state: &State, func: Self) -> Self {
-        if state.enabled(Rule::Decorrelate) {
-            // Not correlated!
-            if func.free().is_empty() {
-                return self.join(func, vec![]);
-            }
+    /// Decorrelate a dependent join whose result feeds an aggregate, using a
+    /// left-outer join at the base so outer tuples with no inner match survive.
+    /// Mirrors the pull-ups in [`decorrelate`], but a `Select`'s correlating
+    /// predicates become the outer join's condition rather than a filter on top
+    /// (which would discard the null-extended rows).
+    fn flatmap_outer(self, state: &State, func: RelId, mut on: Vec<ExprId>) -> RelId {
+        if func.free(state).is_empty() {
+            return self.join_outer(state, func, on);
+        }
 
-            if let RelExpr::Project { src, mut cols } = func {
-                cols.extend(self.att());
-                return self.flatmap(state, *src).project(state, cols);
-            }
+        if let RelExpr::Project { src, mut cols } = state.rel(func) {
+            cols.extend(self.att(state));
+            return self.flatmap_outer(state, src, on).project(state, cols);
+        }
 
-            // Pull up Maps.
-            if let RelExpr::Map { input, exprs } = func {
-                return self.flatmap(state, *input).map(state, exprs);
-            }
+        if let RelExpr::Map { input, exprs } = state.rel(func) {
+            return self.flatmap_outer(state, input, on).map(state, exprs);
         }
 
-        RelExpr::FlatMap {
-            input: Box::new(self),
-            func: Box::new(func),
+        if let RelExpr::Select { src, predicates } = state.rel(func) {
+            on.extend(predicates);
+            return self.flatmap_outer(state, src, on);
         }
+
+        self.join_outer(state, func, on)
     }
 
-    fn project(self, state: &State, cols: HashSet<usize>) -> Self {
-        // Push project into Map if we can.
-        if let RelExpr::Map { exprs, .. } = &self {
-            let map_required_cols: HashSet<_> =
-                exprs.iter().flat_map(|(_, expr)| expr.free()).collect();
-            if cols.is_subset(&map_required_cols) {
-                // Guaranteed to work.
-                if let RelExpr::Map { input, exprs } = self {
-                    return input.project(state, cols).map(state, exprs);
-                }
-            }
-        }
+    fn aggregate(
+        self,
+        state: &State,
+        group_by: HashSet<usize>,
+        aggs: Vec<(usize, AggFunc, ExprId)>,
+    ) -> RelId {
+        state.alloc_rel(RelExpr::Aggregate {
+            input: self,
+            group_by,
+            aggs,
+        })
+    }
 
-        RelExpr::Project {
-            src: Box::new(self),
-            cols,
-        }
+    /// Pushing a `Project` into the `Map` beneath it lives in the
+    /// [`push_project_into_map`] rewrite rule; the builder just interns the node.
+    fn project(self, state: &State, cols: HashSet<usize>) -> RelId {
+        state.alloc_rel(RelExpr::Project { src: self, cols })
     }
 
-    fn att(&self) -> HashSet<usize> {
-        match self {
+    fn att(self, state: &State) -> HashSet<usize> {
+        if let Some(cached) = state.att_cache.borrow().get(&self) {
+            return cached.clone();
+        }
+
+        let att = match state.rel(self) {
             RelExpr::Scan { column_names, .. } => column_names.iter().cloned().collect(),
-            RelExpr::Select { src, .. } => src.att(),
+            RelExpr::Select { src, .. } => src.att(state),
             RelExpr::Join { left, right, .. } => {
-                let mut set = left.att();
-                set.extend(right.att());
+                let mut set = left.att(state);
+                set.extend(right.att(state));
                 set
             }
             RelExpr::Map { input, exprs } => {
-                let mut set = input.att();
+                let mut set = input.att(state);
                 set.extend(exprs.iter().map(|(id, _)| *id));
                 set
             }
             RelExpr::Project { cols, .. } => cols.clone(),
             RelExpr::FlatMap { input, func } => {
-                let mut set = input.att();
-                set.extend(func.att());
+                let mut set = input.att(state);
+                set.extend(func.att(state));
                 set
             }
-        }
+            RelExpr::Aggregate {
+                group_by, aggs, ..
+            } => {
+                let mut set = group_by.clone();
+                set.extend(aggs.iter().map(|(id, _, _)| *id));
+                set
+            }
+        };
+
+        state.att_cache.borrow_mut().insert(self, att.clone());
+        att
     }
 
-    fn free(&self) -> HashSet<usize> {
-        match self {
+    fn free(self, state: &State) -> HashSet<usize> {
+        if let Some(cached) = state.free_cache.borrow().get(&self) {
+            return cached.clone();
+        }
+
+        let free = match state.rel(self) {
             RelExpr::Map { input, exprs } => {
-                let mut set = input.free();
-                for (_, expr) in exprs {
-                    set.extend(expr.free());
+                let mut set = input.free(state);
+                for (_, expr) in &exprs {
+                    set.extend(expr.free(state));
                 }
-                set.difference(&input.att()).copied().collect()
+                set.difference(&input.att(state)).copied().collect()
             }
             RelExpr::FlatMap { input, func } => {
-                let mut set = input.free();
-                set.extend(func.free());
-                set.difference(&input.att()).copied().collect()
+                let mut set = input.free(state);
+                set.extend(func.free(state));
+                set.difference(&input.att(state)).copied().collect()
             }
             RelExpr::Scan { .. } => HashSet::new(),
             RelExpr::Select { src, predicates } => {
-                let mut set = src.free();
-                for expr in predicates {
-                    set.extend(expr.free());
+                let mut set = src.free(state);
+                for expr in &predicates {
+                    set.extend(expr.free(state));
                 }
-                set.difference(&src.att()).copied().collect()
+                set.difference(&src.att(state)).copied().collect()
             }
             RelExpr::Join {
                 left,
                 right,
                 predicates,
+                ..
             } => {
-                let mut set = left.free();
-                set.extend(right.free());
-                for expr in predicates {
-                    set.extend(expr.free());
+                let mut set = left.free(state);
+                set.extend(right.free(state));
+                for expr in &predicates {
+                    set.extend(expr.free(state));
                 }
-                set.difference(&left.att().union(&right.att()).copied().collect())
-                    .copied()
-                    .collect()
+                let att: HashSet<_> = left.att(state).union(&right.att(state)).copied().collect();
+                set.difference(&att).copied().collect()
             }
-            RelExpr::Project { src, .. } => src.free(),
-        }
+            RelExpr::Project { src, .. } => src.free(state),
+            RelExpr::Aggregate {
+                input,
+                group_by,
+                aggs,
+            } => {
+                let mut set = input.free(state);
+                set.extend(group_by.iter().copied());
+                for (_, _, e) in &aggs {
+                    set.extend(e.free(state));
+                }
+                set.difference(&input.att(state)).copied().collect()
+            }
+        };
+
+        state.free_cache.borrow_mut().insert(self, free.clone());
+        free
     }
 
-    fn print(&self, indent: usize, out: &mut String) {
-        match self {
+    fn print(self, state: &State, indent: usize, out: &mut String) {
+        match state.rel(self) {
             RelExpr::Scan {
                 table_name,
                 column_names,
@@ -422,104 +631,827 @@ impl RelExpr {
                 let mut split = "";
                 for predicate in predicates {
                     out.push_str(split);
-                    predicate.print(indent, out);
+                    predicate.print(state, indent, out);
                     split = " && "
                 }
                 out.push_str(")\n");
-                src.print(indent + 2, out);
+                src.print(state, indent + 2, out);
             }
             RelExpr::Join {
+                kind,
                 left,
                 right,
                 predicates,
             } => {
-                out.push_str(&format!("{}-> join(", " ".repeat(indent)));
+                let name = match kind {
+                    JoinKind::Inner => "join",
+                    JoinKind::LeftOuter => "left_outer_join",
+                };
+                out.push_str(&format!("{}-> {}(", " ".repeat(indent), name));
                 let mut split = "";
                 for predicate in predicates {
                     out.push_str(split);
-                    predicate.print(indent, out);
+                    predicate.print(state, indent, out);
                     split = " && "
                 }
                 out.push_str(")\n");
-                left.print(indent + 2, out);
-                right.print(indent + 2, out);
+                left.print(state, indent + 2, out);
+                right.print(state, indent + 2, out);
             }
             RelExpr::Map { input, exprs } => {
                 out.push_str(&format!("{}-> map(\n", " ".repeat(indent)));
                 for (id, expr) in exprs {
                     out.push_str(&format!("{}    @{} <- ", " ".repeat(indent), id));
-                    expr.print(indent, out);
+                    expr.print(state, indent, out);
                     out.push_str(",\n");
                 }
                 out.push_str(&format!("{})\n", " ".repeat(indent + 2)));
-                input.print(indent + 2, out);
+                input.print(state, indent + 2, out);
             }
             RelExpr::Project { src, cols } => {
                 out.push_str(&format!("{}-> project({:?})\n", " ".repeat(indent), cols));
-                src.print(indent + 2, out);
+                src.print(state, indent + 2, out);
             }
             RelExpr::FlatMap { input, func } => {
                 out.push_str(&format!("{}-> flatmap\n", " ".repeat(indent)));
-                input.print(indent + 2, out);
-                out.push_str(&format!("{}  λ.{:?}\n", " ".repeat(indent), func.free()));
-                func.print(indent + 2, out);
+                input.print(state, indent + 2, out);
+                out.push_str(&format!("{}  λ.{:?}\n", " ".repeat(indent), func.free(state)));
+                func.print(state, indent + 2, out);
+            }
+            RelExpr::Aggregate {
+                input,
+                group_by,
+                aggs,
+            } => {
+                out.push_str(&format!(
+                    "{}-> aggregate(by {:?},\n",
+                    " ".repeat(indent),
+                    group_by
+                ));
+                for (id, func, expr) in &aggs {
+                    out.push_str(&format!(
+                        "{}    @{} <- {:?}(",
+                        " ".repeat(indent),
+                        id,
+                        func
+                    ));
+                    expr.print(state, indent, out);
+                    out.push_str("),\n");
+                }
+                out.push_str(&format!("{})\n", " ".repeat(indent + 2)));
+                input.print(state, indent + 2, out);
             }
         }
     }
 }
 
+/// A rewrite transformer: given a node (by id), either propose a replacement or
+/// decline. Rules are pure in spirit — they read the arena through `state` and
+/// only ever allocate fresh nodes — so the driver can apply them anywhere.
+type RewriteRule = fn(RelId, &State) -> Option<RelId>;
+
+impl ExprId {
+    /// Structural (not id) equality: two plans built at different times compare
+    /// equal when they have the same shape. Used to detect the rewrite fixpoint.
+    fn structural_eq(self, other: ExprId, state: &State) -> bool {
+        match (state.expr(self), state.expr(other)) {
+            (Expr::ColRef { id: a }, Expr::ColRef { id: b }) => a == b,
+            (Expr::Int { val: a }, Expr::Int { val: b }) => a == b,
+            (Expr::Eq { left: la, right: ra }, Expr::Eq { left: lb, right: rb })
+            | (Expr::Plus { left: la, right: ra }, Expr::Plus { left: lb, right: rb }) => {
+                la.structural_eq(lb, state) && ra.structural_eq(rb, state)
+            }
+            (Expr::Subquery { expr: a }, Expr::Subquery { expr: b }) => a.structural_eq(b, state),
+            _ => false,
+        }
+    }
+}
+
+impl RelId {
+    /// Bottom-up fold (catamorphism): fold every child to an `A`, then combine
+    /// the resulting `RelExprF<A>` with `f`. Children are visited before parents
+    /// (ids guarantee children were interned first).
+    fn cata<A>(self, state: &State, f: &mut impl FnMut(RelExprF<A>) -> A) -> A {
+        let folded = state.rel(self).map_children(|child| child.cata(state, &mut *f));
+        f(folded)
+    }
+
+    fn structural_eq(self, other: RelId, state: &State) -> bool {
+        let eq_preds = |a: &[ExprId], b: &[ExprId]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.structural_eq(*y, state))
+        };
+        match (state.rel(self), state.rel(other)) {
+            (
+                RelExpr::Scan {
+                    table_name: ta,
+                    column_names: ca,
+                },
+                RelExpr::Scan {
+                    table_name: tb,
+                    column_names: cb,
+                },
+            ) => ta == tb && ca == cb,
+            (
+                RelExpr::Select {
+                    src: sa,
+                    predicates: pa,
+                },
+                RelExpr::Select {
+                    src: sb,
+                    predicates: pb,
+                },
+            ) => sa.structural_eq(sb, state) && eq_preds(&pa, &pb),
+            (
+                RelExpr::Join {
+                    kind: ka,
+                    left: la,
+                    right: ra,
+                    predicates: pa,
+                },
+                RelExpr::Join {
+                    kind: kb,
+                    left: lb,
+                    right: rb,
+                    predicates: pb,
+                },
+            ) => {
+                ka == kb
+                    && la.structural_eq(lb, state)
+                    && ra.structural_eq(rb, state)
+                    && eq_preds(&pa, &pb)
+            }
+            (RelExpr::Project { src: sa, cols: ca }, RelExpr::Project { src: sb, cols: cb }) => {
+                sa.structural_eq(sb, state) && ca == cb
+            }
+            (
+                RelExpr::Map {
+                    input: ia,
+                    exprs: ea,
+                },
+                RelExpr::Map {
+                    input: ib,
+                    exprs: eb,
+                },
+            ) => {
+                ia.structural_eq(ib, state)
+                    && ea.len() == eb.len()
+                    && ea.iter().zip(&eb).all(|((ida, xa), (idb, xb))| {
+                        ida == idb && xa.structural_eq(*xb, state)
+                    })
+            }
+            (
+                RelExpr::FlatMap {
+                    input: ia,
+                    func: fa,
+                },
+                RelExpr::FlatMap {
+                    input: ib,
+                    func: fb,
+                },
+            ) => ia.structural_eq(ib, state) && fa.structural_eq(fb, state),
+            (
+                RelExpr::Aggregate {
+                    input: ia,
+                    group_by: ga,
+                    aggs: aa,
+                },
+                RelExpr::Aggregate {
+                    input: ib,
+                    group_by: gb,
+                    aggs: ab,
+                },
+            ) => {
+                ia.structural_eq(ib, state)
+                    && ga == gb
+                    && aa.len() == ab.len()
+                    && aa.iter().zip(&ab).all(|((ida, fa, ea), (idb, fb, eb))| {
+                        ida == idb && fa == fb && ea.structural_eq(*eb, state)
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// One bottom-up pass: rebuild every node (so rewritten children are wired
+    /// in) and apply the first rule that fires at each node.
+    fn rewrite_once(self, state: &State, rules: &[RewriteRule]) -> RelId {
+        self.cata(state, &mut |node: RelExpr| {
+            let id = state.alloc_rel(node);
+            for rule in rules {
+                if let Some(rewritten) = rule(id, state) {
+                    return rewritten;
+                }
+            }
+            id
+        })
+    }
+
+    /// Apply `rules` to every node, repeating passes until a pass changes
+    /// nothing (fixpoint detected by structural equality).
+    fn rewrite(self, state: &State, rules: &[RewriteRule]) -> RelId {
+        let mut current = self;
+        loop {
+            let next = current.rewrite_once(state, rules);
+            if current.structural_eq(next, state) {
+                return next;
+            }
+            current = next;
+        }
+    }
+}
+
+/// Pull a subquery out of a `Map` binding and into a `FlatMap`, so the scalar
+/// the map computes reads a plain column produced upstream. Fires one binding
+/// at a time; the driver repeats until no binding carries a subquery.
+///
+/// Only `Map` projection bindings are hoisted. A subquery sitting in a `Select`
+/// predicate (a `WHERE (SELECT ...) = k`) is left in place and still runs as a
+/// per-row scalar through [`exec::eval`]; it is never decorrelated into a join.
+/// That path is correct but unoptimized, so a correlated `WHERE` subquery is
+/// *not* evidence that predicate decorrelation works.
+fn hoist_subqueries(id: RelId, state: &State) -> Option<RelId> {
+    if let RelExpr::Map { input, exprs } = state.rel(id) {
+        for i in 0..exprs.len() {
+            if exprs[i].1.has_subquery(state) {
+                let mut rest = exprs.clone();
+                let (col, expr) = rest.remove(i);
+                return Some(input.map(state, rest).hoist(state, col, expr));
+            }
+        }
+    }
+    None
+}
+
+/// Decorrelate a dependent join. The `Project`/`Map`/`Select` arms peel one
+/// layer off the correlated body and rebuild a `FlatMap` the driver revisits,
+/// until the body is uncorrelated and collapses to a join; an `Aggregate` body
+/// is rewritten into joins in a single step. Expressed as a rule so the driver
+/// can apply it wherever a `FlatMap` appears.
+fn decorrelate(id: RelId, state: &State) -> Option<RelId> {
+    let (outer, func) = match state.rel(id) {
+        RelExpr::FlatMap { input, func } => (input, func),
+        _ => return None,
+    };
+
+    // Not correlated: a plain cross join.
+    if func.free(state).is_empty() {
+        return Some(outer.join(state, func, vec![]));
+    }
+
+    if let RelExpr::Project { src, mut cols } = state.rel(func) {
+        cols.extend(outer.att(state));
+        return Some(outer.flatmap(state, src).project(state, cols));
+    }
+
+    // Pull up Maps.
+    if let RelExpr::Map { input, exprs } = state.rel(func) {
+        return Some(outer.flatmap(state, input).map(state, exprs));
+    }
+
+    // Push the dependent join past a Select, keeping its predicates.
+    if let RelExpr::Select { src, predicates } = state.rel(func) {
+        return Some(outer.flatmap(state, src).select(state, predicates));
+    }
+
+    // Push past an Aggregate. A naive dependent join here is an *inner* join,
+    // which drops outer tuples that match no inner rows — the classic "COUNT
+    // bug", where `SELECT (SELECT COUNT(*) ...)` silently omits the zero-count
+    // rows. Instead build the magic domain `D` (the outer, deduplicated on the
+    // correlated columns), aggregate it per key over a left-outer dependent
+    // join, then join the per-key result back onto the full outer relation.
+    if let RelExpr::Aggregate {
+        input,
+        group_by,
+        aggs,
+    } = state.rel(func)
+    {
+        let corr = func.free(state);
+
+        // D, deduplicated on the correlated columns.
+        let domain = outer.aggregate(state, corr.clone(), Vec::new());
+
+        // One group per correlation key (plus the subquery's own keys), over
+        // the left-outer join so keys with no match still appear; their
+        // aggregates fold to the identity (0 for COUNT/SUM).
+        let mut group_by = group_by;
+        group_by.extend(corr.iter().copied());
+        let per_key = domain
+            .flatmap_outer(state, input, Vec::new())
+            .aggregate(state, group_by, aggs);
+
+        // Join back onto the full outer relation to restore its other columns
+        // and its multiplicity. The key columns share ids with the outer, so
+        // rename the per-key side and drop the originals before joining on
+        // equality, then project the originals back.
+        let agg_cols: HashSet<usize> = per_key.att(state).difference(&corr).copied().collect();
+        let mut renames = Vec::new();
+        let mut join_preds = Vec::new();
+        let mut kept = agg_cols.clone();
+        for &c in &corr {
+            let fresh = state.next();
+            renames.push((fresh, state.col_ref(c)));
+            join_preds.push(state.col_ref(c).eq(state, state.col_ref(fresh)));
+            kept.insert(fresh);
+        }
+        let renamed = per_key.map(state, renames).project(state, kept);
+
+        let outputs: HashSet<usize> = outer.att(state).into_iter().chain(agg_cols).collect();
+        return Some(outer.join(state, renamed, join_preds).project(state, outputs));
+    }
+
+    None
+}
+
+/// Predicate pushdown expressed as a standalone rule: move a join predicate
+/// bound entirely by one side down into a `Select` over that side.
+fn push_predicates(id: RelId, state: &State) -> Option<RelId> {
+    if let RelExpr::Join {
+        kind: JoinKind::Inner,
+        left,
+        right,
+        predicates,
+    } = state.rel(id)
+    {
+        for (i, pred) in predicates.iter().enumerate() {
+            if pred.bound_by(state, left) {
+                let mut rest = predicates.clone();
+                let pushed = rest.remove(i);
+                return Some(left.select(state, vec![pushed]).join(state, right, rest));
+            }
+            if pred.bound_by(state, right) {
+                let mut rest = predicates.clone();
+                let pushed = rest.remove(i);
+                return Some(left.join(state, right.select(state, vec![pushed]), rest));
+            }
+        }
+    }
+    None
+}
+
+/// Push a `Project` down into the `Map` it sits on when every projected column
+/// is produced by the map's inputs.
+fn push_project_into_map(id: RelId, state: &State) -> Option<RelId> {
+    if let RelExpr::Project { src, cols } = state.rel(id) {
+        if let RelExpr::Map { input, exprs } = state.rel(src) {
+            let required: HashSet<_> = exprs.iter().flat_map(|(_, e)| e.free(state)).collect();
+            if cols.is_subset(&required) {
+                return Some(input.project(state, cols).map(state, exprs));
+            }
+        }
+    }
+    None
+}
+
+/// Fold a scalar expression bottom-up: subtrees with no free columns collapse
+/// to their value (`Int + Int -> Int`, `Int = Int -> 0/1`). Everything else,
+/// including subqueries, is rebuilt from its already-folded children.
+fn fold_expr(e: ExprId, state: &State) -> ExprId {
+    match state.expr(e) {
+        Expr::Plus { left, right } => {
+            let l = fold_expr(left, state);
+            let r = fold_expr(right, state);
+            if let (Expr::Int { val: a }, Expr::Int { val: b }) = (state.expr(l), state.expr(r)) {
+                state.int(a + b)
+            } else {
+                l.plus(state, r)
+            }
+        }
+        Expr::Eq { left, right } => {
+            let l = fold_expr(left, state);
+            let r = fold_expr(right, state);
+            if let (Expr::Int { val: a }, Expr::Int { val: b }) = (state.expr(l), state.expr(r)) {
+                state.int((a == b) as i64)
+            } else {
+                l.eq(state, r)
+            }
+        }
+        Expr::ColRef { .. } | Expr::Int { .. } | Expr::Subquery { .. } => e,
+    }
+}
+
+/// Constant-fold every scalar carried by a node. Idempotent: once folded a
+/// subtree is structurally stable, so the driver reaches a fixpoint.
+fn constant_fold(id: RelId, state: &State) -> Option<RelId> {
+    let changed = std::cell::Cell::new(false);
+    let mut fold = |e: ExprId| {
+        let folded = fold_expr(e, state);
+        if !folded.structural_eq(e, state) {
+            changed.set(true);
+        }
+        folded
+    };
+
+    let new_node = match state.rel(id) {
+        RelExpr::Select { src, predicates } => RelExpr::Select {
+            src,
+            predicates: predicates.into_iter().map(&mut fold).collect(),
+        },
+        RelExpr::Join {
+            kind,
+            left,
+            right,
+            predicates,
+        } => RelExpr::Join {
+            kind,
+            left,
+            right,
+            predicates: predicates.into_iter().map(&mut fold).collect(),
+        },
+        RelExpr::Map { input, exprs } => RelExpr::Map {
+            input,
+            exprs: exprs.into_iter().map(|(id, e)| (id, fold(e))).collect(),
+        },
+        RelExpr::Aggregate {
+            input,
+            group_by,
+            aggs,
+        } => RelExpr::Aggregate {
+            input,
+            group_by,
+            aggs: aggs
+                .into_iter()
+                .map(|(id, func, e)| (id, func, fold(e)))
+                .collect(),
+        },
+        _ => return None,
+    };
+
+    if changed.get() {
+        Some(state.alloc_rel(new_node))
+    } else {
+        None
+    }
+}
+
+/// Dead-column elimination. Drives a `Project` down toward the leaves using the
+/// required-column set at each node: narrows `Scan` column lists, drops `Map`
+/// bindings nothing above references, and carries the set through `Select`/
+/// `Join`. Each arm either removes a `Project` or strictly narrows a child, so
+/// the rewrite terminates.
+fn prune_columns(id: RelId, state: &State) -> Option<RelId> {
+    let (src, cols) = match state.rel(id) {
+        RelExpr::Project { src, cols } => (src, cols),
+        _ => return None,
+    };
+
+    // Projecting to exactly the columns already produced is a no-op.
+    if cols == src.att(state) && !matches!(state.rel(src), RelExpr::Project { .. }) {
+        return Some(src);
+    }
+
+    match state.rel(src) {
+        RelExpr::Scan {
+            table_name,
+            column_names,
+        } => {
+            let narrowed: Vec<usize> = column_names
+                .into_iter()
+                .filter(|c| cols.contains(c))
+                .collect();
+            Some(state.scan(table_name, narrowed))
+        }
+        RelExpr::Project { src: inner, .. } => Some(inner.project(state, cols)),
+        RelExpr::Map { input, exprs } => {
+            let kept: Vec<(usize, ExprId)> = exprs
+                .iter()
+                .cloned()
+                .filter(|(id, _)| cols.contains(id))
+                .collect();
+            if kept.len() != exprs.len() {
+                // Some bindings are dead above; drop them.
+                return Some(input.map(state, kept).project(state, cols));
+            }
+
+            // Otherwise try to shrink what the input needs to supply.
+            let map_ids: HashSet<usize> = exprs.iter().map(|(id, _)| *id).collect();
+            let mut needed: HashSet<usize> = cols.difference(&map_ids).copied().collect();
+            for (_, e) in &exprs {
+                needed.extend(e.free(state));
+            }
+            let needed: HashSet<usize> = needed.intersection(&input.att(state)).copied().collect();
+            if needed != input.att(state) {
+                Some(input.project(state, needed).map(state, exprs).project(state, cols))
+            } else {
+                None
+            }
+        }
+        RelExpr::Select { src: child, predicates } => {
+            let mut needed = cols.clone();
+            for p in &predicates {
+                needed.extend(p.free(state));
+            }
+            let needed: HashSet<usize> = needed.intersection(&child.att(state)).copied().collect();
+            if needed != child.att(state) {
+                Some(
+                    child
+                        .project(state, needed)
+                        .select(state, predicates)
+                        .project(state, cols),
+                )
+            } else {
+                None
+            }
+        }
+        RelExpr::Join {
+            kind: JoinKind::Inner,
+            left,
+            right,
+            predicates,
+        } => {
+            let mut needed = cols.clone();
+            for p in &predicates {
+                needed.extend(p.free(state));
+            }
+            let lreq: HashSet<usize> = needed.intersection(&left.att(state)).copied().collect();
+            let rreq: HashSet<usize> = needed.intersection(&right.att(state)).copied().collect();
+            if lreq != left.att(state) || rreq != right.att(state) {
+                let left = left.project(state, lreq);
+                let right = right.project(state, rreq);
+                Some(left.join(state, right, predicates).project(state, cols))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Repeatedly apply the enabled rewrite rules until the plan stops changing.
+fn optimize(root: RelId, state: &State) -> RelId {
+    let mut rules: Vec<RewriteRule> = Vec::new();
+    if state.enabled(Rule::Hoist) {
+        rules.push(hoist_subqueries);
+    }
+    if state.enabled(Rule::Decorrelate) {
+        rules.push(decorrelate);
+    }
+    if state.enabled(Rule::ConstantFold) {
+        rules.push(constant_fold);
+    }
+    if state.enabled(Rule::PruneColumns) {
+        rules.push(prune_columns);
+    }
+    if state.enabled(Rule::PushPredicates) {
+        rules.push(push_predicates);
+    }
+    if state.enabled(Rule::PushProjectIntoMap) {
+        rules.push(push_project_into_map);
+    }
+    root.rewrite(state, &rules)
+}
+
 fn main() {
+    // Front-end: lower SQL text and run the optimizer over it.
+    let state = State::new();
+    state.enable(Rule::Hoist);
+    state.enable(Rule::Decorrelate);
+    state.enable(Rule::ConstantFold);
+    state.enable(Rule::PruneColumns);
+    state.enable(Rule::PushPredicates);
+    state.enable(Rule::PushProjectIntoMap);
+
+    let sql = "SELECT k, (SELECT v FROM b WHERE j = k) FROM a";
+    match parse::parse(sql, &state) {
+        Ok(plan) => {
+            let plan = optimize(plan, &state);
+            let mut out = String::new();
+            plan.print(&state, 0, &mut out);
+            println!("{}\n{}", sql, out);
+        }
+        Err(err) => println!("parse error at {:?}: {}", err.span, err.message),
+    }
+
+    // Back-end: build a correlated COUNT by hand and run it, showing the
+    // decorrelation keeps outer rows whose subquery matches nothing.
     let state = State::new();
     state.enable(Rule::Hoist);
     state.enable(Rule::Decorrelate);
 
     let a = state.next();
-    let b = state.next();
-    let x = state.next();
-    let y = state.next();
+    let k = state.next();
+    let cnt = state.next();
+
+    let inner = {
+        let scan = state.scan("x".into(), vec![k]);
+        let sel = scan.select(&state, vec![state.col_ref(k).eq(&state, state.col_ref(a))]);
+        sel.aggregate(
+            &state,
+            HashSet::new(),
+            vec![(cnt, AggFunc::Count, state.col_ref(k))],
+        )
+        .project(&state, [cnt].into_iter().collect())
+    };
+    let plan = state
+        .scan("d".into(), vec![a])
+        .flatmap(&state, inner.map(&state, vec![(cnt, state.col_ref(cnt))]));
+    let plan = optimize(plan, &state);
 
-    let sum_col = state.next();
+    let mut catalog = exec::Catalog::new();
+    catalog.insert("d", vec![row([(a, 1)]), row([(a, 2)])]);
+    catalog.insert("x", vec![row([(k, 1)]), row([(k, 1)])]);
 
-    let v = RelExpr::scan("a".into(), vec![a, b]).map(
+    let mut rows = exec::execute(plan, &state, &catalog, exec::Row::new());
+    rows.sort_by_key(|r| r[&a]);
+    println!("\ncounts:");
+    for r in rows {
+        println!("  a={} count={}", r[&a], r[&cnt]);
+    }
+
+    // A flat aggregate over each supported function.
+    let g = state.next();
+    let v = state.next();
+    let (c, s, lo, hi) = (state.next(), state.next(), state.next(), state.next());
+    let agg = state.scan("t".into(), vec![g, v]).aggregate(
         &state,
+        [g].into_iter().collect(),
         vec![
-            // (
-            //     state.next(),
-            //     Expr::int(3).plus(Expr::Subquery {
-            //         expr: Box::new(
-            //             RelExpr::scan("x".into(), vec![x, y]).project([x].into_iter().collect()),
-            //         ),
-            //     }),
-            // ),
-            (
-                state.next(),
-                Expr::int(4).plus(Expr::Subquery {
-                    expr: Box::new(
-                        RelExpr::scan("x".into(), vec![x, y])
-                            .project(&state, [x].into_iter().collect())
-                            .map(&state, [(sum_col, Expr::col_ref(x).plus(Expr::col_ref(a)))])
-                            .project(&state, [sum_col].into_iter().collect()),
-                    ),
-                }),
-            ),
+            (c, AggFunc::Count, state.col_ref(v)),
+            (s, AggFunc::Sum, state.col_ref(v)),
+            (lo, AggFunc::Min, state.col_ref(v)),
+            (hi, AggFunc::Max, state.col_ref(v)),
         ],
     );
+    catalog.insert(
+        "t",
+        vec![row([(g, 0), (v, 3)]), row([(g, 0), (v, 5)]), row([(g, 0), (v, 1)])],
+    );
+    for r in exec::execute(agg, &state, &catalog, exec::Row::new()) {
+        println!(
+            "\nagg: count={} sum={} min={} max={}",
+            r[&c], r[&s], r[&lo], r[&hi]
+        );
+    }
+}
+
+/// Build a row literal from `(column id, value)` pairs.
+fn row(pairs: impl IntoIterator<Item = (usize, i64)>) -> exec::Row {
+    pairs.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{execute, Catalog, Row};
+
+    /// A correlated `COUNT` must report 0 for outer rows that match nothing,
+    /// rather than dropping them (the "COUNT bug").
+    #[test]
+    fn correlated_count_keeps_unmatched_outer_rows() {
+        let state = State::new();
+        state.enable(Rule::Hoist);
+        state.enable(Rule::Decorrelate);
+
+        let a = state.next();
+        let k = state.next();
+        let cnt = state.next();
+
+        let inner = {
+            let scan = state.scan("x".into(), vec![k]);
+            let sel = scan.select(&state, vec![state.col_ref(k).eq(&state, state.col_ref(a))]);
+            sel.aggregate(
+                &state,
+                HashSet::new(),
+                vec![(cnt, AggFunc::Count, state.col_ref(k))],
+            )
+            .project(&state, [cnt].into_iter().collect())
+        };
+        let plan = state
+            .scan("d".into(), vec![a])
+            .flatmap(&state, inner.map(&state, vec![(cnt, state.col_ref(cnt))]));
+        let plan = optimize(plan, &state);
+
+        let mut catalog = Catalog::new();
+        catalog.insert("d", vec![row([(a, 1)]), row([(a, 2)])]);
+        catalog.insert("x", vec![row([(k, 1)]), row([(k, 1)])]);
+
+        let mut got: Vec<(i64, i64)> = execute(plan, &state, &catalog, Row::new())
+            .iter()
+            .map(|r| (r[&a], r[&cnt]))
+            .collect();
+        got.sort();
+        assert_eq!(got, vec![(1, 2), (2, 0)]);
+    }
+
+    /// Each aggregate function folds its group down to the expected value.
+    #[test]
+    fn aggregate_functions() {
+        let state = State::new();
+        let g = state.next();
+        let v = state.next();
+        let (c, s, lo, hi) = (state.next(), state.next(), state.next(), state.next());
+
+        let plan = state.scan("t".into(), vec![g, v]).aggregate(
+            &state,
+            [g].into_iter().collect(),
+            vec![
+                (c, AggFunc::Count, state.col_ref(v)),
+                (s, AggFunc::Sum, state.col_ref(v)),
+                (lo, AggFunc::Min, state.col_ref(v)),
+                (hi, AggFunc::Max, state.col_ref(v)),
+            ],
+        );
 
-    // let v = RelExpr::scan("a".into(), vec![a, b]).map(
-    //     &state,
-    //     vec![(
-    //         state.next(),
-    //         Expr::Subquery {
-    //             expr: Box::new(
-    //                 RelExpr::scan("x".into(), vec![x, y])
-    //                     .project(&state, [x].into_iter().collect()),
-    //             ),
-    //         },
-    //     )],
-    // );
-
-    let mut out = String::new();
-    v.print(0, &mut out);
-
-    println!("{}", out);
+        let mut catalog = Catalog::new();
+        catalog.insert(
+            "t",
+            vec![row([(g, 0), (v, 3)]), row([(g, 0), (v, 5)]), row([(g, 0), (v, 1)])],
+        );
+
+        let rows = execute(plan, &state, &catalog, Row::new());
+        assert_eq!(rows.len(), 1);
+        let r = &rows[0];
+        assert_eq!((r[&c], r[&s], r[&lo], r[&hi]), (3, 9, 1, 5));
+    }
+
+    /// A `Scan` only exposes the columns it declares, even when the backing
+    /// table stores more.
+    #[test]
+    fn scan_projects_to_declared_columns() {
+        let state = State::new();
+        let (a, b) = (state.next(), state.next());
+
+        let plan = state.scan("t".into(), vec![a]);
+        let mut catalog = Catalog::new();
+        catalog.insert("t", vec![row([(a, 1), (b, 9)])]);
+
+        let rows = execute(plan, &state, &catalog, Row::new());
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].contains_key(&a));
+        assert!(!rows[0].contains_key(&b));
+    }
+
+    /// Constant folding collapses arithmetic on literals wherever scalars live,
+    /// including inside aggregate expressions.
+    #[test]
+    fn constant_folding_reaches_aggregates() {
+        let state = State::new();
+        state.enable(Rule::ConstantFold);
+        let g = state.next();
+        let s = state.next();
+
+        let folded_sum = state.int(2).plus(&state, state.int(3));
+        let plan = state.scan("t".into(), vec![g]).aggregate(
+            &state,
+            [g].into_iter().collect(),
+            vec![(s, AggFunc::Sum, folded_sum)],
+        );
+        let plan = optimize(plan, &state);
+
+        if let RelExpr::Aggregate { aggs, .. } = state.rel(plan) {
+            assert!(matches!(state.expr(aggs[0].2), Expr::Int { val: 5 }));
+        } else {
+            panic!("expected an aggregate at the root");
+        }
+    }
+
+    /// The parser resolves a name introduced by an outer query to that outer
+    /// binding when it reappears unqualified in a subquery (a correlation).
+    #[test]
+    fn parser_correlates_shared_names() {
+        let state = State::new();
+        state.enable(Rule::Hoist);
+        state.enable(Rule::Decorrelate);
+        let plan = parse::parse("SELECT k, (SELECT v FROM b WHERE j = k) FROM a", &state)
+            .expect("parse");
+        // `k` is introduced by the outer query and reused unqualified in the
+        // subquery, so it binds outward: the whole plan is closed.
+        assert!(plan.free(&state).is_empty(), "top level is closed");
+        // With hoisting and decorrelation on, the correlated subquery lowers to
+        // a join rather than staying a nested scalar.
+        let plan = optimize(plan, &state);
+        let mut out = String::new();
+        plan.print(&state, 0, &mut out);
+        assert!(out.contains("join"));
+    }
+
+    /// A subquery in a `WHERE` predicate is *not* hoisted or decorrelated: it
+    /// survives optimization as a scalar `Subquery` and is evaluated per row by
+    /// the executor. This pins the documented limitation so the per-row path
+    /// isn't mistaken for working predicate decorrelation.
+    #[test]
+    fn where_subquery_stays_a_scalar() {
+        let state = State::new();
+        state.enable(Rule::Hoist);
+        state.enable(Rule::Decorrelate);
+        let plan = parse::parse("SELECT k FROM a WHERE k = (SELECT v FROM b)", &state)
+            .expect("parse");
+        let plan = optimize(plan, &state);
+
+        // The predicate subquery is still a nested scalar (printed as `λ.(`),
+        // not lowered to a join the way a `Map` binding subquery would be.
+        let mut out = String::new();
+        plan.print(&state, 0, &mut out);
+        assert!(out.contains("λ.("), "predicate subquery remained a scalar");
+
+        // It nonetheless executes correctly through the per-row scalar path.
+        // The parser mints ids in reference order: `k` is the first column
+        // bound (id 0), `v` the second (id 1).
+        let (k, v) = (0, 1);
+        let mut catalog = Catalog::new();
+        catalog.insert("a", vec![row([(k, 1)]), row([(k, 2)])]);
+        catalog.insert("b", vec![row([(v, 1)])]);
+        let rows = execute(plan, &state, &catalog, Row::new());
+        let got: Vec<i64> = rows.iter().map(|r| r[&k]).collect();
+        assert_eq!(got, vec![1]);
+    }
 }
@@ -0,0 +1,351 @@
+//! A tiny SQL front-end that lowers a `SELECT ... FROM ... WHERE ...` subset
+//! into the planner's `RelExpr`/`Expr` arena, so callers don't have to hand
+//! build plan trees.
+//!
+//! The grammar is deliberately small:
+//!
+//! ```text
+//! query   := SELECT scalar (',' scalar)* FROM ident (WHERE scalar (AND scalar)*)?
+//! scalar  := add ('=' add)?
+//! add     := primary ('+' primary)*
+//! primary := int | colref | '(' query ')' | '(' scalar ')'
+//! colref  := ident ('.' ident)?
+//! ```
+//!
+//! Column names are assigned fresh ids through `State::next` and recorded in a
+//! scope stack; a name referenced inside a subquery but introduced by an outer
+//! query resolves to the outer id, which is exactly the correlation the
+//! `Decorrelate` rule consumes.
+
+use std::collections::HashMap;
+
+use crate::{ExprId, RelId, State};
+
+/// Byte offsets into the source string, `[start, end)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    Select,
+    From,
+    Where,
+    And,
+    Ident(String),
+    Int(i64),
+    Eq,
+    Plus,
+    Comma,
+    Dot,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    span: Span,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, ParseError> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let tok = match c {
+            '=' => {
+                i += 1;
+                Tok::Eq
+            }
+            '+' => {
+                i += 1;
+                Tok::Plus
+            }
+            ',' => {
+                i += 1;
+                Tok::Comma
+            }
+            '.' => {
+                i += 1;
+                Tok::Dot
+            }
+            '(' => {
+                i += 1;
+                Tok::LParen
+            }
+            ')' => {
+                i += 1;
+                Tok::RParen
+            }
+            c if c.is_ascii_digit() => {
+                while i < bytes.len() && (bytes[i] as char).is_ascii_digit() {
+                    i += 1;
+                }
+                let text = &src[start..i];
+                let val = text.parse().map_err(|_| ParseError {
+                    message: format!("invalid integer literal {:?}", text),
+                    span: Span { start, end: i },
+                })?;
+                Tok::Int(val)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                while i < bytes.len() && {
+                    let ch = bytes[i] as char;
+                    ch.is_alphanumeric() || ch == '_'
+                } {
+                    i += 1;
+                }
+                match src[start..i].to_ascii_uppercase().as_str() {
+                    "SELECT" => Tok::Select,
+                    "FROM" => Tok::From,
+                    "WHERE" => Tok::Where,
+                    "AND" => Tok::And,
+                    _ => Tok::Ident(src[start..i].to_string()),
+                }
+            }
+            other => {
+                return Err(ParseError {
+                    message: format!("unexpected character {:?}", other),
+                    span: Span { start, end: start + 1 },
+                })
+            }
+        };
+
+        tokens.push(Token {
+            tok,
+            span: Span { start, end: i },
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// A single query level's symbol table: the fresh ids minted for the columns of
+/// its `FROM` table.
+#[derive(Default)]
+struct Scope {
+    cols: Vec<usize>,
+    names: HashMap<String, usize>,
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    src_len: usize,
+    state: &'a State,
+    scopes: Vec<Scope>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|t| &t.tok)
+    }
+
+    fn span_here(&self) -> Span {
+        match self.tokens.get(self.pos) {
+            Some(t) => t.span,
+            None => Span {
+                start: self.src_len,
+                end: self.src_len,
+            },
+        }
+    }
+
+    fn bump(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        if t.is_some() {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn expect(&mut self, want: &Tok) -> Result<(), ParseError> {
+        if self.peek() == Some(want) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(ParseError {
+                message: format!("expected {:?}", want),
+                span: self.span_here(),
+            })
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, ParseError> {
+        let span = self.span_here();
+        match self.bump().map(|t| t.tok) {
+            Some(Tok::Ident(name)) => Ok(name),
+            _ => Err(ParseError {
+                message: "expected identifier".to_string(),
+                span,
+            }),
+        }
+    }
+
+    /// Resolve a column name to a stable id, searching the innermost scope
+    /// first and walking outward. A name that exists nowhere is a fresh column
+    /// of the innermost query's table.
+    ///
+    /// Because the grammar is schema-less we can't know a table's columns up
+    /// front, so the first reference to an unqualified name decides its home:
+    /// if some enclosing query already bound that name, the inner reference
+    /// resolves to the outer id and becomes a correlation rather than a new
+    /// local column.
+    fn resolve(&mut self, name: &str) -> usize {
+        for scope in self.scopes.iter().rev() {
+            if let Some(id) = scope.names.get(name) {
+                return *id;
+            }
+        }
+        let id = self.state.next();
+        let scope = self.scopes.last_mut().expect("scope");
+        scope.names.insert(name.to_string(), id);
+        scope.cols.push(id);
+        id
+    }
+
+    fn query(&mut self) -> Result<RelId, ParseError> {
+        self.scopes.push(Scope::default());
+
+        self.expect(&Tok::Select)?;
+        let mut projections = vec![self.scalar()?];
+        while self.peek() == Some(&Tok::Comma) {
+            self.bump();
+            projections.push(self.scalar()?);
+        }
+
+        self.expect(&Tok::From)?;
+        let table = self.ident()?;
+
+        let mut predicates = Vec::new();
+        if self.peek() == Some(&Tok::Where) {
+            self.bump();
+            predicates.push(self.scalar()?);
+            while self.peek() == Some(&Tok::And) {
+                self.bump();
+                predicates.push(self.scalar()?);
+            }
+        }
+
+        let scope = self.scopes.pop().expect("scope");
+
+        let mut rel = self.state.scan(table, scope.cols);
+        if !predicates.is_empty() {
+            rel = rel.select(self.state, predicates);
+        }
+
+        // Bare column references project straight through; computed scalars get
+        // a fresh binding via `Map`.
+        let mut bindings = Vec::new();
+        let mut outputs = Vec::new();
+        for expr in projections {
+            match self.state.expr(expr) {
+                crate::Expr::ColRef { id } => outputs.push(id),
+                _ => {
+                    let id = self.state.next();
+                    bindings.push((id, expr));
+                    outputs.push(id);
+                }
+            }
+        }
+
+        if !bindings.is_empty() {
+            rel = rel.map(self.state, bindings);
+        }
+        rel = rel.project(self.state, outputs.into_iter().collect());
+
+        Ok(rel)
+    }
+
+    fn scalar(&mut self) -> Result<ExprId, ParseError> {
+        let left = self.add()?;
+        if self.peek() == Some(&Tok::Eq) {
+            self.bump();
+            let right = self.add()?;
+            return Ok(left.eq(self.state, right));
+        }
+        Ok(left)
+    }
+
+    fn add(&mut self) -> Result<ExprId, ParseError> {
+        let mut left = self.primary()?;
+        while self.peek() == Some(&Tok::Plus) {
+            self.bump();
+            let right = self.primary()?;
+            left = left.plus(self.state, right);
+        }
+        Ok(left)
+    }
+
+    fn primary(&mut self) -> Result<ExprId, ParseError> {
+        let span = self.span_here();
+        match self.peek() {
+            Some(Tok::Int(val)) => {
+                let val = *val;
+                self.bump();
+                Ok(self.state.int(val))
+            }
+            Some(Tok::Ident(_)) => {
+                let mut name = self.ident()?;
+                if self.peek() == Some(&Tok::Dot) {
+                    self.bump();
+                    let field = self.ident()?;
+                    name = format!("{}.{}", name, field);
+                }
+                let id = self.resolve(&name);
+                Ok(self.state.col_ref(id))
+            }
+            Some(Tok::LParen) => {
+                self.bump();
+                let expr = if self.peek() == Some(&Tok::Select) {
+                    let sub = self.query()?;
+                    self.state.subquery(sub)
+                } else {
+                    self.scalar()?
+                };
+                self.expect(&Tok::RParen)?;
+                Ok(expr)
+            }
+            _ => Err(ParseError {
+                message: "expected a scalar expression".to_string(),
+                span,
+            }),
+        }
+    }
+}
+
+/// Parse a SQL query into a `RelExpr` ready to feed into the optimizer.
+pub fn parse(src: &str, state: &State) -> Result<RelId, ParseError> {
+    let tokens = tokenize(src)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        src_len: src.len(),
+        state,
+        scopes: Vec::new(),
+    };
+    let rel = parser.query()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(ParseError {
+            message: "trailing input after query".to_string(),
+            span: parser.span_here(),
+        });
+    }
+    Ok(rel)
+}